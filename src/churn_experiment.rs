@@ -0,0 +1,386 @@
+//! Reusable core for measuring DHT record churn.
+//!
+//! This consolidates the publish/poll/resolve loops that used to be
+//! copy-pasted (with subtly different behavior) across `main`,
+//! `main_check_churn` and `resolve`. A [`ChurnExperiment`] is configured once
+//! via [`ChurnExperiment::builder`] and its methods return plain structs so
+//! callers (CLI binaries or embedders) decide what to do with the results
+//! (print them, write them to CSV, feed them into another system, ...).
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use mainline::Dht;
+use pkarr::{Client, PublicKey};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::helpers::count_dht_nodes_storing_packet;
+use crate::latency::LatencyHistogram;
+use crate::published_key::PublishedKey;
+
+/// A single self-healing republish, recorded when [`ChurnExperiment::republish_below`]
+/// is set and a key's storing-node count drops below the configured threshold.
+#[derive(Debug, Clone)]
+pub struct RepublishEvent {
+    pub pubkey: PublicKey,
+    /// How many nodes were still storing the key when the republish triggered.
+    pub nodes_count: u8,
+    /// How long it had been since this key was last (re)published.
+    pub interval_since_previous_publish: Duration,
+}
+
+/// Result of [`ChurnExperiment::run_churn_loop`].
+#[derive(Debug)]
+pub struct ChurnLoopResult {
+    /// The input records, with `churned_at` updated to reflect their final state.
+    pub records: Vec<PublishedKey>,
+    /// Latency of every resolve/node-count check issued during the loop.
+    pub resolve_latencies: LatencyHistogram,
+    /// Number of passes over the full record set that were completed.
+    pub passes: usize,
+}
+
+/// Lock-free snapshot of a [`ChurnExperiment::run_churn_loop`] run in progress,
+/// updated once per pass with the pass count, churn fraction, the sum of storing
+/// nodes observed across every key, and resolve-latency percentiles. Exists so a
+/// caller can expose it over a scrape endpoint (see [`crate::metrics_server::serve_json`])
+/// or emit it as NDJSON without waiting for the loop to finish.
+#[derive(Debug, Default)]
+pub struct ChurnMetrics {
+    pub elapsed_s: AtomicU64,
+    pub passes: AtomicUsize,
+    pub churned: AtomicUsize,
+    pub total: AtomicUsize,
+    /// Sum, across every key, of the storing-node count observed in the latest pass.
+    pub global_node_count: AtomicU64,
+    pub resolve_latency_p50_us: AtomicU64,
+    pub resolve_latency_p90_us: AtomicU64,
+    pub resolve_latency_p99_us: AtomicU64,
+    pub resolve_latency_max_us: AtomicU64,
+}
+
+impl ChurnMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders the current snapshot as a single NDJSON object.
+    pub fn to_json(&self) -> String {
+        let total = self.total.load(Ordering::Relaxed);
+        let churned = self.churned.load(Ordering::Relaxed);
+        let churn_fraction = if total == 0 { 0.0 } else { churned as f64 / total as f64 };
+        format!(
+            "{{\"elapsed_s\":{},\"passes\":{},\"churned\":{},\"total\":{},\"churn_fraction\":{:.4},\"global_node_count\":{},\"resolve_latency_us\":{{\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{}}}}}",
+            self.elapsed_s.load(Ordering::Relaxed),
+            self.passes.load(Ordering::Relaxed),
+            churned,
+            total,
+            churn_fraction,
+            self.global_node_count.load(Ordering::Relaxed),
+            self.resolve_latency_p50_us.load(Ordering::Relaxed),
+            self.resolve_latency_p90_us.load(Ordering::Relaxed),
+            self.resolve_latency_p99_us.load(Ordering::Relaxed),
+            self.resolve_latency_max_us.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Outcome of a single [`ChurnExperiment::resolve_sample`] call.
+#[derive(Debug)]
+pub struct ResolveSampleOutcome {
+    pub key: PublicKey,
+    pub resolved: bool,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChurnExperiment {
+    num_records: usize,
+    ttl_s: u32,
+    stop_fraction: f64,
+    sleep_duration: Duration,
+    max_duration: Duration,
+    verify: bool,
+    republish_below: Option<u8>,
+}
+
+impl ChurnExperiment {
+    pub fn builder() -> ChurnExperimentBuilder {
+        ChurnExperimentBuilder::default()
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.num_records
+    }
+
+    /// Publishes `num_records` fresh records into the DHT, each with the
+    /// configured TTL. Verifies the replica count right after publishing
+    /// when `verify` is set. Stops early, returning the records published so
+    /// far, once `token` is cancelled.
+    pub async fn publish(
+        &self,
+        client: &Client,
+        token: &CancellationToken,
+    ) -> (Vec<PublishedKey>, LatencyHistogram) {
+        let dht = client.dht().ok();
+        let mut records = Vec::with_capacity(self.num_records);
+        let mut latencies = LatencyHistogram::new();
+
+        for i in 0..self.num_records {
+            let key = PublishedKey::random();
+            let packet = key.create_packet_with_ttl(self.ttl_s);
+
+            let publish_start = Instant::now();
+            let publish_result = tokio::select! {
+                _ = token.cancelled() => break,
+                result = client.publish(&packet, None) => result,
+            };
+            if let Err(e) = publish_result {
+                tracing::error!("Failed to publish {} record: {e:?}", key.public_key());
+                continue;
+            }
+            let elapsed = publish_start.elapsed();
+            latencies.record(elapsed.as_micros() as u64);
+
+            if self.verify {
+                if let Some(dht) = &dht {
+                    let found_count = count_dht_nodes_storing_packet(&key.public_key(), dht).await;
+                    tracing::info!(
+                        "{}/{} Published {} on {found_count} nodes within {}ms",
+                        i + 1,
+                        self.num_records,
+                        key.public_key(),
+                        elapsed.as_millis()
+                    );
+                    records.push(key);
+                    continue;
+                }
+            }
+
+            tracing::info!(
+                "{}/{} Published {} within {}ms",
+                i + 1,
+                self.num_records,
+                key.public_key(),
+                elapsed.as_millis()
+            );
+            records.push(key);
+        }
+
+        (records, latencies)
+    }
+
+    /// Repeatedly polls `records` for how many nodes still store them, until
+    /// either `stop_fraction` of the set has churned or `max_duration`
+    /// elapses. A record is considered churned once no node responds to a
+    /// `get_mutable` query for it; it is marked available again as soon as
+    /// it is seen resolvable.
+    ///
+    /// Cancelling `token` (e.g. on Ctrl+C) stops the loop cleanly at the next
+    /// `tokio::select!` point and returns the records in their current state
+    /// rather than aborting mid-flight.
+    ///
+    /// When [`republish_below`](ChurnExperimentBuilder::republish_below) is set, a key whose
+    /// storing-node count drops below the threshold is re-signed with a fresh TTL and
+    /// re-published right away via `client`, and the event is sent to `republish_log` (if
+    /// `Some`) as it happens, rather than buffered for the duration of the run. `client`
+    /// must be `Some` for this to take effect.
+    ///
+    /// When `metrics` is `Some`, it is updated at the end of every pass, so a caller can
+    /// poll it (e.g. over an HTTP endpoint, or from a periodic NDJSON-emitting task)
+    /// for live progress instead of waiting for the loop to return.
+    pub async fn run_churn_loop(
+        &self,
+        client: Option<&Client>,
+        dht: &Dht,
+        mut records: Vec<PublishedKey>,
+        token: &CancellationToken,
+        metrics: Option<&ChurnMetrics>,
+        republish_log: Option<mpsc::Sender<RepublishEvent>>,
+    ) -> ChurnLoopResult {
+        let total_keys = records.len();
+        let mut resolve_latencies = LatencyHistogram::new();
+        let start = Instant::now();
+        let mut last_published: HashMap<PublicKey, Instant> = HashMap::new();
+        let mut passes = 0usize;
+
+        'passes: loop {
+            let mut pass_node_count: u64 = 0;
+            for key in records.iter_mut() {
+                tokio::select! {
+                    _ = token.cancelled() => break 'passes,
+                    _ = tokio::time::sleep(self.sleep_duration) => {}
+                }
+
+                let pubkey = key.public_key();
+                let check_start = Instant::now();
+                let nodes_count = tokio::select! {
+                    _ = token.cancelled() => break 'passes,
+                    count = count_dht_nodes_storing_packet(&pubkey, dht) => count,
+                };
+                resolve_latencies.record(check_start.elapsed().as_micros() as u64);
+
+                pass_node_count += nodes_count as u64;
+                if nodes_count == 0 {
+                    key.mark_as_churned();
+                } else {
+                    key.mark_as_available();
+                }
+
+                if let (Some(threshold), Some(client)) = (self.republish_below, client) {
+                    if nodes_count < threshold {
+                        let packet = key.create_packet_with_ttl(self.ttl_s);
+                        let republish_result = tokio::select! {
+                            _ = token.cancelled() => break 'passes,
+                            result = client.publish(&packet, None) => result,
+                        };
+                        if let Err(e) = republish_result {
+                            tracing::error!("Failed to republish {pubkey}: {e:?}");
+                        } else {
+                            let now = Instant::now();
+                            let previous = last_published
+                                .insert(pubkey.clone(), now)
+                                .unwrap_or(key.published_at);
+                            if let Some(log) = &republish_log {
+                                let event = RepublishEvent {
+                                    pubkey: pubkey.clone(),
+                                    nodes_count,
+                                    interval_since_previous_publish: now.duration_since(previous),
+                                };
+                                if log.send(event).await.is_err() {
+                                    tracing::warn!("Republish log receiver dropped; no longer recording republishes");
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            passes += 1;
+
+            let churned = records.iter().filter(|key| key.is_churned()).count();
+            let churn_fraction = churned as f64 / total_keys as f64;
+            tracing::info!("Pass {passes}: churn fraction {:.2}%", churn_fraction * 100.0);
+
+            if let Some(metrics) = metrics {
+                metrics.elapsed_s.store(start.elapsed().as_secs(), Ordering::Relaxed);
+                metrics.passes.store(passes, Ordering::Relaxed);
+                metrics.churned.store(churned, Ordering::Relaxed);
+                metrics.total.store(total_keys, Ordering::Relaxed);
+                metrics.global_node_count.store(pass_node_count, Ordering::Relaxed);
+                metrics.resolve_latency_p50_us.store(resolve_latencies.p50(), Ordering::Relaxed);
+                metrics.resolve_latency_p90_us.store(resolve_latencies.p90(), Ordering::Relaxed);
+                metrics.resolve_latency_p99_us.store(resolve_latencies.p99(), Ordering::Relaxed);
+                metrics.resolve_latency_max_us.store(resolve_latencies.max(), Ordering::Relaxed);
+            }
+
+            if churn_fraction >= self.stop_fraction || start.elapsed() >= self.max_duration {
+                break;
+            }
+        }
+
+        ChurnLoopResult {
+            records,
+            resolve_latencies,
+            passes,
+        }
+    }
+
+    /// Resolves one randomly-chosen key from `keys` and reports whether it
+    /// was found along with how long the resolve took.
+    pub async fn resolve_sample(&self, client: &Client, keys: &[PublicKey]) -> ResolveSampleOutcome {
+        let mut bytes = [0u8; 8];
+        getrandom::fill(&mut bytes).expect("getrandom");
+        let index = u64::from_le_bytes(bytes) as usize % keys.len();
+        let key = keys[index].clone();
+
+        let start = Instant::now();
+        let resolved = client.resolve(&key).await.is_some();
+        ResolveSampleOutcome {
+            key,
+            resolved,
+            elapsed: start.elapsed(),
+        }
+    }
+}
+
+/// Builder for [`ChurnExperiment`], mirroring the `Client`/`SignedPacket`
+/// builder style used elsewhere in this crate.
+#[derive(Debug, Clone)]
+pub struct ChurnExperimentBuilder {
+    num_records: usize,
+    ttl_s: u32,
+    stop_fraction: f64,
+    sleep_duration: Duration,
+    max_duration: Duration,
+    verify: bool,
+    republish_below: Option<u8>,
+}
+
+impl Default for ChurnExperimentBuilder {
+    fn default() -> Self {
+        Self {
+            num_records: 500,
+            ttl_s: 604_800,
+            stop_fraction: 1.1,
+            sleep_duration: Duration::from_millis(1000),
+            max_duration: Duration::from_secs(72 * 3600),
+            verify: false,
+            republish_below: None,
+        }
+    }
+}
+
+impl ChurnExperimentBuilder {
+    pub fn num_records(mut self, num_records: usize) -> Self {
+        self.num_records = num_records;
+        self
+    }
+
+    pub fn ttl_s(mut self, ttl_s: u32) -> Self {
+        self.ttl_s = ttl_s;
+        self
+    }
+
+    pub fn stop_fraction(mut self, stop_fraction: f64) -> Self {
+        self.stop_fraction = stop_fraction;
+        self
+    }
+
+    pub fn sleep_duration(mut self, sleep_duration: Duration) -> Self {
+        self.sleep_duration = sleep_duration;
+        self
+    }
+
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = max_duration;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Enables self-healing republish: whenever a key's storing-node count drops below
+    /// `threshold`, it is re-signed with a fresh TTL and re-published immediately.
+    pub fn republish_below(mut self, threshold: u8) -> Self {
+        self.republish_below = Some(threshold);
+        self
+    }
+
+    pub fn build(self) -> ChurnExperiment {
+        ChurnExperiment {
+            num_records: self.num_records,
+            ttl_s: self.ttl_s,
+            stop_fraction: self.stop_fraction,
+            sleep_duration: self.sleep_duration,
+            max_duration: self.max_duration,
+            verify: self.verify,
+            republish_below: self.republish_below,
+        }
+    }
+}
@@ -3,47 +3,64 @@ use std::time::Duration;
 use mainline::Dht;
 use pkarr::{Client, PublicKey};
 use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
 
+use crate::latency::LatencyHistogram;
 use crate::published_key::PublishedKey;
-
-
+use crate::rate_limiter::TokenBucket;
 
 /// Queries the public key and returns how many nodes responded with the packet.
+///
+/// `mainline`'s `get_mutable`/`get_mutable_detailed` only ever yield the stored
+/// [`mainline::MutableItem`] (the value) plus an aggregate [`mainline::rpc::GetMutableOutcome`]
+/// once the lookup is done; neither exposes the identity of the responding nodes, so this
+/// can only report a count, not *which* nodes stored the packet.
 pub async fn count_dht_nodes_storing_packet(pubkey: &PublicKey, client: &Dht) -> u8 {
-    let c = client.clone();
-    let p = pubkey.clone();
-    let handle = tokio::task::spawn_blocking(move || {
-        let stream = c.get_mutable(p.as_bytes(), None, None);
-        let mut response_count: u8 = 0;
-    
-        for _ in stream {
-            response_count += 1;
-        }
-    
-        response_count
-    });
-
-    handle.await.unwrap()
+    let async_dht = client.clone().as_async();
+    let detailed = async_dht.get_mutable_detailed(pubkey.as_bytes(), None, None);
+    let outcome = detailed.outcome.recv().await;
+    outcome.values.min(u8::MAX as u32) as u8
 }
 
 
-// Publishes x number of packets. Checks if they are actually available
-pub async fn publish_records(num_records: usize, thread_id: usize, verify: bool) -> Vec<PublishedKey> {
+// Publishes x number of packets. Checks if they are actually available.
+// Stops early, returning what was published so far, once `token` is cancelled.
+//
+// When `limiter` is set, one token is acquired from it before each publish,
+// so the aggregate rate across every thread sharing the same limiter is held
+// at the limiter's configured rate.
+pub async fn publish_records(
+    num_records: usize,
+    thread_id: usize,
+    verify: bool,
+    token: &CancellationToken,
+    limiter: Option<&TokenBucket>,
+) -> (Vec<PublishedKey>, LatencyHistogram) {
     let client = Client::builder().no_relays().build().unwrap();
     let dht = client.dht().unwrap();
     dht.clone().as_async().bootstrapped().await;
     tracing::info!("DHT client id: {}", dht.info().id());
     let mut records = vec![];
+    let mut latencies = LatencyHistogram::new();
 
     for i in 0..num_records {
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
         let instant = Instant::now();
         let key = PublishedKey::random();
         let packet = key.create_packet();
-        if let Err(e) = client.publish(&packet, None).await {
+        let publish_result = tokio::select! {
+            _ = token.cancelled() => break,
+            result = client.publish(&packet, None) => result,
+        };
+        if let Err(e) = publish_result {
             tracing::error!("Failed to publish {} record: {e:?}", key.public_key());
             continue;
         }
-        let publish_time = instant.elapsed().as_millis();
+        let elapsed = instant.elapsed();
+        latencies.record(elapsed.as_micros() as u64);
+        let publish_time = elapsed.as_millis();
         if verify {
             let found_count = count_dht_nodes_storing_packet(&key.public_key(), &dht).await;
             tracing::info!("- t{thread_id:<2} {i:>3}/{num_records} Published {} on {found_count:<2} nodes within {publish_time}ms", key.public_key());
@@ -53,5 +70,5 @@ pub async fn publish_records(num_records: usize, thread_id: usize, verify: bool)
 
         records.push(key);
     }
-    records
+    (records, latencies)
 }
\ No newline at end of file
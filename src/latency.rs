@@ -0,0 +1,339 @@
+//! Logarithmically-bucketed latency histogram.
+//!
+//! Churn runs can issue hundreds of thousands of publishes/resolves, so we
+//! can't afford to keep every sample around just to compute percentiles at
+//! the end. Instead, samples (in microseconds) are bucketed by their
+//! power-of-two magnitude, with a fixed number of linear sub-buckets inside
+//! each magnitude for precision. This bounds memory to a small, fixed-size
+//! array while keeping relative error low across the whole latency range.
+
+const MAGNITUDES: usize = 65; // `64 - v.leading_zeros()` is in 0..=64
+const SUB_BUCKETS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; MAGNITUDES * SUB_BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    /// Records a single latency sample, in microseconds.
+    pub fn record(&mut self, micros: u64) {
+        let index = Self::bucket_index(micros);
+        self.counts[index] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    /// Merges the samples of `other` into `self`, e.g. to combine the
+    /// per-thread histograms of parallel publishers.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    fn bucket_index(v: u64) -> usize {
+        let magnitude = (64 - v.leading_zeros()) as usize;
+        if magnitude == 0 {
+            return 0;
+        }
+        let base = 1u64 << (magnitude - 1);
+        // Widen to u128 so `(v - base) * SUB_BUCKETS` can't overflow for the top magnitudes.
+        let sub = ((((v - base) as u128 * SUB_BUCKETS as u128) / base as u128) as u64)
+            .min(SUB_BUCKETS as u64 - 1) as usize;
+        magnitude * SUB_BUCKETS + sub
+    }
+
+    /// Representative value (microseconds) of the bucket at `index`.
+    fn bucket_value(index: usize) -> u64 {
+        let magnitude = index / SUB_BUCKETS;
+        let sub = index % SUB_BUCKETS;
+        if magnitude == 0 {
+            return 0;
+        }
+        let base = 1u64 << (magnitude - 1);
+        base + ((sub as u128 * base as u128) / SUB_BUCKETS as u128) as u64 + base / (SUB_BUCKETS as u64 * 2)
+    }
+
+    /// Approximate value at percentile `p` (0.0..=1.0), in microseconds.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let target = ((p * self.total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0u64;
+        for (index, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            cumulative += count;
+            if cumulative >= target {
+                return Self::bucket_value(index);
+            }
+        }
+        self.max
+    }
+
+    pub fn p50(&self) -> u64 {
+        self.percentile(0.50)
+    }
+
+    pub fn p90(&self) -> u64 {
+        self.percentile(0.90)
+    }
+
+    pub fn p99(&self) -> u64 {
+        self.percentile(0.99)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const ATOMIC_SUB_BUCKETS: usize = 8;
+
+/// Lock-free variant of [`LatencyHistogram`] for hot paths where many tasks
+/// record samples concurrently (e.g. one per publish across dozens of
+/// in-flight tasks) and a `Mutex`-guarded histogram would serialize them.
+/// Share one instance behind an `Arc` and call [`record`](Self::record) from
+/// any task; percentiles are read by snapshotting the counters.
+#[derive(Debug)]
+pub struct AtomicLatencyHistogram {
+    counts: Vec<std::sync::atomic::AtomicU64>,
+    max: std::sync::atomic::AtomicU64,
+}
+
+impl AtomicLatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: (0..MAGNITUDES * ATOMIC_SUB_BUCKETS)
+                .map(|_| std::sync::atomic::AtomicU64::new(0))
+                .collect(),
+            max: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    /// Records a single latency sample, in microseconds. Safe to call from
+    /// any number of concurrent tasks without external synchronization.
+    pub fn record(&self, micros: u64) {
+        use std::sync::atomic::Ordering;
+        let index = Self::bucket_index(micros);
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn bucket_index(v: u64) -> usize {
+        let magnitude = (64 - v.max(1).leading_zeros()) as usize;
+        if magnitude == 0 {
+            return 0;
+        }
+        let base = 1u64 << (magnitude - 1);
+        let sub = ((v - base) * ATOMIC_SUB_BUCKETS as u64 / base).min(ATOMIC_SUB_BUCKETS as u64 - 1) as usize;
+        magnitude * ATOMIC_SUB_BUCKETS + sub
+    }
+
+    /// Representative value (microseconds) of the bucket at `index`.
+    fn bucket_value(index: usize) -> u64 {
+        let magnitude = index / ATOMIC_SUB_BUCKETS;
+        let sub = index % ATOMIC_SUB_BUCKETS;
+        if magnitude == 0 {
+            return 0;
+        }
+        let base = 1u64 << (magnitude - 1);
+        base + (sub as u64 * base) / ATOMIC_SUB_BUCKETS as u64 + base / (ATOMIC_SUB_BUCKETS as u64 * 2)
+    }
+
+    /// Approximate value at percentile `p` (0.0..=1.0), in microseconds,
+    /// computed from a point-in-time snapshot of the counters.
+    pub fn percentile(&self, p: f64) -> u64 {
+        use std::sync::atomic::Ordering;
+        let snapshot: Vec<u64> = self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        let total: u64 = snapshot.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let target = ((p * total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0u64;
+        for (index, &count) in snapshot.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            cumulative += count;
+            if cumulative >= target {
+                return Self::bucket_value(index);
+            }
+        }
+        self.max()
+    }
+
+    pub fn p50(&self) -> u64 {
+        self.percentile(0.50)
+    }
+
+    pub fn p90(&self) -> u64 {
+        self.percentile(0.90)
+    }
+
+    pub fn p99(&self) -> u64 {
+        self.percentile(0.99)
+    }
+
+    pub fn p999(&self) -> u64 {
+        self.percentile(0.999)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts
+            .iter()
+            .map(|c| c.load(std::sync::atomic::Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl Default for AtomicLatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_histogram_percentiles_are_zero() {
+        let hist = LatencyHistogram::new();
+        assert_eq!(hist.p50(), 0);
+        assert_eq!(hist.p99(), 0);
+        assert_eq!(hist.max(), 0);
+        assert_eq!(hist.count(), 0);
+    }
+
+    #[test]
+    fn percentile_of_uniform_samples_is_their_bucket_midpoint() {
+        let mut hist = LatencyHistogram::new();
+        for _ in 0..1000 {
+            hist.record(100);
+        }
+        // 100us falls in the [100, 104) sub-bucket of the 64..128 band, whose
+        // representative (midpoint) value is 102us.
+        assert_eq!(hist.p50(), 102);
+        assert_eq!(hist.p99(), 102);
+        assert_eq!(hist.max(), 100);
+        assert_eq!(hist.count(), 1000);
+    }
+
+    #[test]
+    fn percentile_reflects_skew_towards_the_high_end() {
+        let mut hist = LatencyHistogram::new();
+        for _ in 0..99 {
+            hist.record(100);
+        }
+        hist.record(1_000_000);
+        assert_eq!(hist.max(), 1_000_000);
+        // 99 of 100 samples are ~100us, so p50/p90 should land in that bucket.
+        assert!(hist.p50() <= 110);
+        assert!(hist.p90() <= 110);
+        // Only the last sample is past the 99th percentile, so p99 still lands in the
+        // ~100us bucket (midpoint 102us); the outlier only shows up at the very top,
+        // in the [983040, 1015808) bucket whose midpoint is 999424us.
+        assert_eq!(hist.p99(), 102);
+        assert_eq!(hist.percentile(1.0), 999_424);
+    }
+
+    #[test]
+    fn merge_combines_counts_and_keeps_the_larger_max() {
+        let mut a = LatencyHistogram::new();
+        a.record(50);
+        let mut b = LatencyHistogram::new();
+        b.record(500);
+        a.merge(&b);
+        assert_eq!(a.count(), 2);
+        assert_eq!(a.max(), 500);
+    }
+
+    #[test]
+    fn bucket_index_is_non_decreasing_in_value() {
+        // Representative values recovered from increasing samples must never
+        // decrease, or percentile lookups could regress as more data comes in.
+        let samples = [0, 1, 2, 7, 8, 63, 64, 65, 1_000, 1_000_000, u64::MAX];
+        let mut last_value = 0;
+        for &v in &samples {
+            let index = LatencyHistogram::bucket_index(v);
+            let value = LatencyHistogram::bucket_value(index);
+            assert!(value >= last_value, "value for {v} ({value}) regressed below {last_value}");
+            last_value = value;
+        }
+    }
+
+    #[test]
+    fn atomic_empty_histogram_percentiles_are_zero() {
+        let hist = AtomicLatencyHistogram::new();
+        assert_eq!(hist.p50(), 0);
+        assert_eq!(hist.p999(), 0);
+        assert_eq!(hist.max(), 0);
+        assert_eq!(hist.count(), 0);
+    }
+
+    #[test]
+    fn atomic_percentile_of_uniform_samples_is_the_sample_value() {
+        let hist = AtomicLatencyHistogram::new();
+        for _ in 0..1000 {
+            hist.record(200);
+        }
+        assert_eq!(hist.p50(), 200);
+        assert_eq!(hist.p999(), 200);
+        assert_eq!(hist.max(), 200);
+        assert_eq!(hist.count(), 1000);
+    }
+
+    #[test]
+    fn atomic_record_is_safe_from_concurrent_tasks() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hist = Arc::new(AtomicLatencyHistogram::new());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let hist = Arc::clone(&hist);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        hist.record(10);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(hist.count(), 800);
+        assert_eq!(hist.max(), 10);
+    }
+}
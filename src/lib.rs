@@ -0,0 +1,37 @@
+//! Library core for the pkarr DHT churn experiments.
+//!
+//! The binaries in this crate (`main`, `main_check_churn`, `resolve`, ...) are
+//! thin CLI wrappers over [`ChurnExperiment`]. Pulling the measurement logic
+//! in here means the publish/churn/resolve loops are implemented once instead
+//! of drifting apart across every binary that needs them.
+
+pub mod churn_experiment;
+pub mod helpers;
+pub mod latency;
+pub mod metrics_server;
+pub mod published_key;
+pub mod rate_limiter;
+
+pub use churn_experiment::{ChurnExperiment, ChurnMetrics};
+pub use helpers::count_dht_nodes_storing_packet;
+pub use metrics_server::serve_json;
+pub use published_key::PublishedKey;
+pub use rate_limiter::TokenBucket;
+
+use tokio_util::sync::CancellationToken;
+
+/// Returns a [`CancellationToken`] that gets cancelled the moment Ctrl+C is
+/// pressed. Pass it (or clones of it) into [`ChurnExperiment`] methods so
+/// long-running publish/churn loops can wind down cleanly instead of being
+/// killed mid-flight.
+pub fn ctrlc_token() -> CancellationToken {
+    let token = CancellationToken::new();
+    let shutdown = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("Ctrl+C received, shutting down...");
+            shutdown.cancel();
+        }
+    });
+    token
+}
@@ -16,21 +16,16 @@
 //!
 //! 1. **Publishing Phase**: A specified number of records (defaults to 500) are published sequentially into the DHT with a given TTL.
 //!    The publishing progress is logged along with the average time per publish.
-//! 2. **Churn Phase**: In a loop, the experiment periodically attempts to resolve the published records.
+//! 2. **Churn Phase**: In a loop, the experiment periodically checks how many nodes still store each published record.
 //!    The experiment stops when either:
 //!    - A preconfigured fraction of the records have churned (defaults to 0.8), or
 //!    - A specified maximum observation duration (defaults to 12 hours) has elapsed.
-//!    
-//!    When a record is no longer resolvable, its churn time (i.e. the elapsed time since publication) is recorded
-//!    in a CSV file. Remaining active records at the end of the experiment are logged with a churn time of 0.
 //!
 //! ## Limitations
 //!
 //! - **Network Variability**: The measured churn times may be influenced by transient network latency and load.
 //! - **Time Granularity**: The sleep duration between resolution passes limits the precision of the churn time measurements.
 //! - **Incomplete Churns**: Some records may not churn during the observation period, potentially skewing the data.
-//! - **Assumption on Churn**: We assume a record has churned the first time `pkarr.resolve()` returns `None`.
-//!   This might happen for different reasons and does not necessarily mean the record was permanently lost.
 //!
 //! ## Configuration
 //!
@@ -42,16 +37,34 @@
 //! - `sleep_duration_ms`: Duration (in milliseconds) to wait between successive resolves (default: 1000 ms).
 //! - `max_hours`: Maximum duration (in hours) for the churn monitoring phase (default: 10 hours). The experiment stops
 //!   after this duration even if the `stop_fraction` threshold is not met.
+//! - `republish_below`: If set, self-heals each record by re-publishing it (with a fresh TTL)
+//!   whenever its storing-node count drops below this threshold. Every such republish is logged
+//!   to `republishes.csv` along with the interval since the record was last (re)published, which
+//!   empirically answers "how often do I need to republish to keep this record resolvable?".
+//! - `metrics_format`: `text` (default) prints the existing human-readable progress lines;
+//!   `json` additionally emits one NDJSON object per report to stdout, so long multi-hour
+//!   runs can be monitored by an aggregator instead of a human tailing a terminal.
+//! - `metrics_addr`: If set, serves the current pass/churn-fraction snapshot as JSON over
+//!   HTTP at this address (see [`pkarr_churn::metrics_server`]), so an external collector
+//!   can poll live progress instead of parsing stdout.
 //!
+//! This binary is a thin wrapper over the [`pkarr_churn::ChurnExperiment`] library API; see that module for the
+//! actual publish/poll loop.
 
-use clap::Parser;
-use pkarr::{Client, Keypair, PublicKey, SignedPacket};
+use clap::{Parser, ValueEnum};
+use pkarr::Client;
+use pkarr_churn::{
+    churn_experiment::RepublishEvent, ctrlc_token, latency::LatencyHistogram, serve_json,
+    ChurnExperiment, ChurnMetrics,
+};
 use std::{
-    collections::HashMap,
     fs::File,
     io::{BufWriter, Write},
-    time::{Duration, Instant},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::sync::mpsc;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -75,6 +88,24 @@ struct Cli {
     /// Maximum duration (in hours) for the churn monitoring phase
     #[arg(long, default_value_t = 72)]
     max_hours: u64,
+
+    /// Re-publish a record whenever its storing-node count drops below this threshold
+    #[arg(long)]
+    republish_below: Option<u8>,
+
+    /// How to emit periodic progress reports
+    #[arg(long, value_enum, default_value_t = MetricsFormat::Text)]
+    metrics_format: MetricsFormat,
+
+    /// If set, serves the current churn progress as JSON over HTTP at this address
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MetricsFormat {
+    Text,
+    Json,
 }
 
 #[tokio::main]
@@ -85,150 +116,169 @@ async fn main() -> anyhow::Result<()> {
         .maximum_ttl(0)
         .no_relays()
         .build()?;
+    let dht = client.dht().unwrap();
+    dht.clone().as_async().bootstrapped().await;
+    let token = ctrlc_token();
 
-    let start = Instant::now();
-    let published_records = publish_records(&client, cli.num_records, cli.ttl_s).await;
+    let mut experiment_builder = ChurnExperiment::builder()
+        .num_records(cli.num_records)
+        .ttl_s(cli.ttl_s)
+        .stop_fraction(cli.stop_fraction)
+        .sleep_duration(Duration::from_millis(cli.sleep_duration_ms))
+        .max_duration(Duration::from_secs(cli.max_hours * 3600));
+    if let Some(threshold) = cli.republish_below {
+        experiment_builder = experiment_builder.republish_below(threshold);
+    }
+    let experiment = experiment_builder.build();
+
+    let latency_file = File::create("latency_percentiles.csv")?;
+    let mut latency_writer = BufWriter::new(latency_file);
+    writeln!(latency_writer, "pass,p50_us,p90_us,p99_us,max_us")?;
+
+    let (published_records, publish_latencies) = experiment.publish(&client, &token).await;
+    println!("Published {} records", published_records.len());
     println!(
-        "Published {} records in {:?}",
-        published_records.len(),
-        start.elapsed()
+        "Publish latency (us): p50={} p90={} p99={} max={}",
+        publish_latencies.p50(),
+        publish_latencies.p90(),
+        publish_latencies.p99(),
+        publish_latencies.max()
     );
+    write_latency_row(&mut latency_writer, "publish", &publish_latencies)?;
 
     println!("Wait one minute before starting to resolve records");
 
-    let max_duration = Duration::from_secs(cli.max_hours * 3600);
-    run_churn_loop(
-        client,
-        published_records,
-        cli.stop_fraction,
-        cli.sleep_duration_ms,
-        max_duration,
-    )
-    .await?;
-
-    Ok(())
-}
-
-async fn publish_records(
-    client: &Client,
-    num_records: usize,
-    ttl_s: u32,
-) -> Vec<(PublicKey, Instant)> {
-    let mut records = Vec::with_capacity(num_records);
-    let mut total_publish_duration: u64 = 0;
-    for i in 0..num_records {
-        let keypair = Keypair::random();
-        let packet = match SignedPacket::builder()
-            .txt(
-                "_experiment".try_into().unwrap(),
-                "dht-test".try_into().unwrap(),
-                ttl_s,
-            )
-            .sign(&keypair)
-        {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Failed to build packet: {e}");
-                continue;
+    let metrics = Arc::new(ChurnMetrics::new());
+    if let Some(addr) = cli.metrics_addr {
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(e) = serve_json(addr, move || metrics.to_json()).await {
+                tracing::error!("Metrics server on {addr} exited: {e:?}");
             }
-        };
-
-        let publish_start = Instant::now();
-        if let Err(e) = client.publish(&packet, None).await {
-            eprintln!("Failed to publish record: {e:?}");
-            continue;
-        }
-        let elapsed = publish_start.elapsed();
-        total_publish_duration += elapsed.as_micros() as u64;
+        });
+    }
+    let json_reporter = matches!(cli.metrics_format, MetricsFormat::Json).then(|| {
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                println!("{}", metrics.to_json());
+            }
+        })
+    });
 
-        records.push((keypair.public_key(), Instant::now()));
+    // Stream republish events to republishes.csv as they happen, instead of buffering
+    // the full history in memory for the duration of a potentially multi-day run.
+    let (republish_tx, republish_rx) = mpsc::channel(1024);
+    let republish_writer = spawn_republish_writer(republish_rx);
 
-        let avg_secs = (total_publish_duration as f64) / ((i + 1) as f64 * 1_000_000.0);
-        println!(
-            "Published {} records: avg time per record: {:.6} s",
-            i + 1,
-            avg_secs
-        );
+    let result = experiment
+        .run_churn_loop(
+            Some(&client),
+            &dht,
+            published_records,
+            &token,
+            Some(&metrics),
+            Some(republish_tx),
+        )
+        .await;
+    if let Some(reporter) = json_reporter {
+        reporter.abort();
     }
+    println!(
+        "Ran {} churn passes. {} / {} records churned.",
+        result.passes,
+        result.records.iter().filter(|key| key.is_churned()).count(),
+        result.records.len()
+    );
+    write_latency_row(&mut latency_writer, "resolve", &result.resolve_latencies)?;
 
-    records
-}
+    let churns_file = File::create("churns.csv")?;
+    let mut churns_writer = BufWriter::new(churns_file);
+    writeln!(churns_writer, "pubkey,churned")?;
+    for key in &result.records {
+        writeln!(churns_writer, "{},{}", key.public_key(), key.is_churned())?;
+    }
+    churns_writer.flush()?;
 
-async fn run_churn_loop(
-    client: Client,
-    verified_records: Vec<(PublicKey, Instant)>,
-    stop_fraction: f64,
-    sleep_duration_ms: u64,
-    max_duration: Duration,
-) -> anyhow::Result<()> {
-    let total_keys = verified_records.len();
-    let mut potential_churn: HashMap<PublicKey, Instant> = HashMap::new();
+    // `run_churn_loop` owned (and has now dropped) the sender, so the writer task's
+    // channel is closed and it will flush, tally up, and return on its own.
+    let (republish_count, republish_intervals) = republish_writer.await??;
 
-    let churn_start = Instant::now();
-    loop {
+    if republish_count > 0 {
         println!(
-            "\n--- Churn pass; {} keys are currently marked as unresolved ---",
-            potential_churn.len()
+            "Observed {republish_count} republishes. Time until node count fell below threshold (s): p50={} p90={} p99={} max={}",
+            republish_intervals.p50() / 1_000_000,
+            republish_intervals.p90() / 1_000_000,
+            republish_intervals.p99() / 1_000_000,
+            republish_intervals.max() / 1_000_000,
         );
+        println!(
+            "To keep ~90% of records continuously resolvable, republish at least every {}s.",
+            // Only ~10% of records fell below the threshold sooner than the 10th percentile.
+            republish_intervals.percentile(0.10) / 1_000_000
+        );
+    }
 
-        for (pubkey, _publish_instant) in &verified_records {
-            tokio::time::sleep(Duration::from_millis(sleep_duration_ms)).await;
+    Ok(())
+}
 
-            // Try to resolve the key.
-            if client.resolve(pubkey).await.is_some() {
-                // If it had been marked as unresolved before, clear the flag.
-                if potential_churn.remove(pubkey).is_some() {
-                    println!("Key {pubkey} recovered; clearing failure record.");
-                } else {
-                    println!("Key {pubkey} is resolvable.");
-                }
-            } else {
-                // If this is the first time we see a failure, record the time.
-                if !potential_churn.contains_key(pubkey) {
-                    potential_churn.insert(pubkey.clone(), Instant::now());
-                    println!("Key {pubkey} unresolved; marking first failure timestamp.");
-                } else {
-                    println!("Key {pubkey} remains unresolved.");
-                }
-            }
-        }
+/// Spawns the writer task that owns `republishes.csv`, appending each [`RepublishEvent`]
+/// sent to it as it happens (rather than buffering the full run's history in memory) and
+/// flushing on an interval. Once `events` closes, returns the total count and a histogram
+/// of observed intervals, so the caller can print a final summary without having kept
+/// every event around itself.
+fn spawn_republish_writer(
+    mut events: mpsc::Receiver<RepublishEvent>,
+) -> tokio::task::JoinHandle<anyhow::Result<(usize, LatencyHistogram)>> {
+    tokio::spawn(async move {
+        let mut writer = BufWriter::new(File::create("republishes.csv")?);
+        writeln!(writer, "pubkey,nodes_count,interval_since_previous_publish_s")?;
 
-        // Save the current churn data to CSV at the end of each loop iteration.
-        {
-            let file = File::create("churns_500_7.csv")?;
-            let mut writer = BufWriter::new(file);
-            writeln!(writer, "pubkey,time_s")?;
-            for (pubkey, publish_instant) in &verified_records {
-                if let Some(failure_instant) = potential_churn.get(pubkey) {
-                    let churn_time = failure_instant.duration_since(*publish_instant).as_secs();
-                    writeln!(writer, "{pubkey},{churn_time}")?;
-                } else {
-                    writeln!(writer, "{pubkey},0")?;
+        let mut count = 0usize;
+        let mut intervals = LatencyHistogram::new();
+        let mut flush_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    match event {
+                        Some(event) => {
+                            writeln!(
+                                writer,
+                                "{},{},{}",
+                                event.pubkey,
+                                event.nodes_count,
+                                event.interval_since_previous_publish.as_secs()
+                            )?;
+                            count += 1;
+                            intervals.record(event.interval_since_previous_publish.as_micros() as u64);
+                        }
+                        None => break,
+                    }
                 }
+                _ = flush_interval.tick() => writer.flush()?,
             }
-            writer.flush()?;
         }
 
-        let churn_fraction = potential_churn.len() as f64 / total_keys as f64;
-        println!("Current churn fraction: {:.2}%", churn_fraction * 100.0);
-
-        // Stop if the unresolved fraction threshold is reached.
-        if churn_fraction >= stop_fraction {
-            println!(
-                "Stop fraction reached ({}%). Ending churn monitoring.",
-                churn_fraction * 100.0
-            );
-            break;
-        }
+        writer.flush()?;
+        Ok((count, intervals))
+    })
+}
 
-        // Also stop if the maximum duration has been exceeded.
-        if churn_start.elapsed() >= max_duration {
-            println!(
-                "Maximum duration of {} hours reached. Ending churn monitoring.",
-                max_duration.as_secs() / 3600
-            );
-            break;
-        }
-    }
+fn write_latency_row(
+    writer: &mut BufWriter<File>,
+    pass: &str,
+    latencies: &pkarr_churn::latency::LatencyHistogram,
+) -> anyhow::Result<()> {
+    writeln!(
+        writer,
+        "{pass},{},{},{},{}",
+        latencies.p50(),
+        latencies.p90(),
+        latencies.p99(),
+        latencies.max()
+    )?;
+    writer.flush()?;
     Ok(())
 }
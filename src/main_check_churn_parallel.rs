@@ -16,25 +16,17 @@
 
 
 use clap::{command, Parser};
-use helpers::count_dht_nodes_storing_packet;
-use mainline::{Dht, DhtBuilder};
-use pkarr::Keypair;
-use published_key::PublishedKey;
-use tokio::time::{sleep, Instant};
-use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    }, time::Duration,
-};
+use mainline::DhtBuilder;
+use pkarr::{Keypair, PublicKey};
+use pkarr_churn::{ctrlc_token, helpers::count_dht_nodes_storing_packet, PublishedKey};
+use tokio::{task::JoinSet, time::Instant};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 use tracing::{ info, level_filters::LevelFilter};
 use tracing_subscriber::EnvFilter;
 use rand::seq::SliceRandom;
 use rand::rng;
 
-mod helpers;
-mod published_key;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
@@ -55,15 +47,7 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(EnvFilter::from_default_env().add_directive(LevelFilter::INFO.into()))
         .init();
 
-    // Set up the Ctrl+C handler
-    let ctrlc_pressed: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-    let r = ctrlc_pressed.clone();
-    ctrlc::set_handler(move || {
-        r.store(true, Ordering::SeqCst);
-        println!("Ctrl+C detected, shutting down...");
-    })
-    .expect("Error setting Ctrl+C handler");
-
+    let token = ctrlc_token();
     println!("Press Ctrl+C to stop...");
 
     println!("Read published_secrets.txt");
@@ -71,7 +55,7 @@ async fn main() -> anyhow::Result<()> {
 
     println!("Read {} keys", published_keys.len());
 
-    run_churn_loop(published_keys, &ctrlc_pressed, cli.threads).await;
+    let _checked_keys = run_churn_loop(published_keys, &token, cli.threads).await;
 
     Ok(())
 }
@@ -91,7 +75,7 @@ fn read_keys() -> Vec<PublishedKey> {
 
 async fn run_churn_loop(
     mut all_keys: Vec<PublishedKey>,
-    ctrlc_pressed: &Arc<AtomicBool>,
+    token: &CancellationToken,
     thread_count: usize,
 ) -> Vec<PublishedKey> {
 
@@ -102,51 +86,43 @@ async fn run_churn_loop(
     let chunk_size = all_keys_count / thread_count;
     let chunks = all_keys.chunks(chunk_size).map(|chunk| chunk.to_vec()).collect::<Vec<_>>();
 
-    let mut handles = vec![];
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
     for (thread_id, chunk) in chunks.into_iter().enumerate() {
-        let handle = tokio::spawn(async move {
-            check_chunks(chunk, thread_id).await
+        let token = token.clone();
+        tasks.spawn(async move {
+            check_chunks(chunk, thread_id, &token).await
         });
-        handles.push(handle);
-    };
+    }
 
-    let start = Instant::now();
-    loop {
-        let all_finished = handles
-            .iter()
-            .map(|handle| handle.is_finished())
-            .reduce(|a, b| a && b)
-            .unwrap();
-        if all_finished {
-            break;
-        }
-        if ctrlc_pressed.load(Ordering::Relaxed) {
-            break;
-        }
-        sleep(Duration::from_millis(250)).await;
+    let mut checked_keys = vec![];
+    while let Some(joined) = tasks.join_next().await {
+        checked_keys.extend(joined.unwrap());
     }
 
-    let passed = start.elapsed().as_secs();
+    let passed = start.elapsed().as_secs().max(1);
     let rate = all_keys_count as f64 / passed as f64;
     tracing::info!("Resolved {all_keys_count} keys in {passed}s at {rate:.2} keys/s");
 
-    if ctrlc_pressed.load(Ordering::Relaxed) {
-        std::process::exit(0);
-    }
-    
-
-    all_keys
+    checked_keys
 }
 
 
-async fn check_chunks(mut chunk: Vec<PublishedKey>, thread_id: usize) {
+async fn check_chunks(
+    mut chunk: Vec<PublishedKey>,
+    thread_id: usize,
+    token: &CancellationToken,
+) -> Vec<PublishedKey> {
     let client = DhtBuilder::default().request_timeout(Duration::from_millis(1000)).build().unwrap();
     client.clone().as_async().bootstrapped().await;
 
     let keys_count = chunk.len();
     for (i, key) in chunk.iter_mut().enumerate() {
         let pubkey = &key.public_key();
-        let nodes_count = count_dht_nodes_storing_packet(pubkey, &client).await;
+        let nodes_count = tokio::select! {
+            _ = token.cancelled() => break,
+            count = count_dht_nodes_storing_packet(pubkey, &client) => count,
+        };
         // Try to resolve the key.
         if nodes_count > 0 {
             info!("- t{thread_id:<3} {i:>2}/{} Key {pubkey} is resolvable on {nodes_count} nodes.", keys_count);
@@ -156,6 +132,7 @@ async fn check_chunks(mut chunk: Vec<PublishedKey>, thread_id: usize) {
             key.mark_as_churned();
         }
     }
+    chunk
 }
 
 
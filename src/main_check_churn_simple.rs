@@ -3,13 +3,9 @@
 //!
 //! Run with `cargo run --bin main_check_churn`.
 
-use helpers::count_dht_nodes_storing_packet;
 use mainline::Dht;
 use pkarr::{Keypair, PublicKey};
-use published_key::PublishedKey;
-
-mod helpers;
-mod published_key;
+use pkarr_churn::{count_dht_nodes_storing_packet, PublishedKey};
 
 
 #[tokio::main]
@@ -5,23 +5,21 @@
 
 use clap::Parser;
 
-use helpers::publish_records;
-use published_key::PublishedKey;
+use pkarr_churn::ctrlc_token;
+use pkarr_churn::helpers::publish_records;
+use pkarr_churn::latency::LatencyHistogram;
+use pkarr_churn::{PublishedKey, TokenBucket};
 use std::{
-    process,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::{Duration, Instant},
+    fs::File,
+    io::{BufWriter, Write},
+    sync::Arc,
+    time::Instant,
 };
-use tokio::time::sleep;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::EnvFilter;
 
-mod helpers;
-mod published_key;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
@@ -36,6 +34,10 @@ struct Cli {
     /// Verify how many nodes stored the value
     #[arg(long, default_value_t = 1)]
     verify: usize,
+
+    /// Target aggregate publish rate in records/sec across all threads (0 = unlimited)
+    #[arg(long, default_value_t = 0.0)]
+    publish_rate: f64,
 }
 
 #[tokio::main]
@@ -47,22 +49,33 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(EnvFilter::from_default_env().add_directive(LevelFilter::INFO.into()))
         .init();
 
-    // Set up the Ctrl+C handler
-    let ctrlc_pressed: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-    let r = ctrlc_pressed.clone();
-    ctrlc::set_handler(move || {
-        r.store(true, Ordering::SeqCst);
-        println!("Ctrl+C detected, shutting down...");
-    })
-    .expect("Error setting Ctrl+C handler");
-
+    let token = ctrlc_token();
     println!("Press Ctrl+C to stop...");
 
     let cli = Cli::parse();
-    
+
     let should_verify = cli.verify > 0;
     info!("Publish {} records. Verify: {should_verify}", cli.num_records);
-    let published_keys = publish_parallel(cli.num_records, cli.threads, should_verify, &ctrlc_pressed).await;
+    if cli.publish_rate > 0.0 {
+        info!("Target publish rate: {} records/sec", cli.publish_rate);
+    }
+    let (published_keys, publish_latencies) = publish_parallel(
+        cli.num_records,
+        cli.threads,
+        should_verify,
+        cli.publish_rate,
+        &token,
+    )
+    .await;
+
+    info!(
+        "Publish latency (us): p50={} p90={} p99={} max={}",
+        publish_latencies.p50(),
+        publish_latencies.p90(),
+        publish_latencies.p99(),
+        publish_latencies.max()
+    );
+    write_latency_csv(&publish_latencies)?;
 
     // Turn into a hex list and write to file
     let pubkeys = published_keys
@@ -83,49 +96,71 @@ async fn publish_parallel(
     num_records: usize,
     threads: usize,
     verify: bool,
-    ctrlc_pressed: &Arc<AtomicBool>,
-) -> Vec<PublishedKey> {
+    publish_rate: f64,
+    token: &CancellationToken,
+) -> (Vec<PublishedKey>, LatencyHistogram) {
+    // One limiter shared across every thread's JoinSet task, so the
+    // *aggregate* rate (not each thread's own rate) matches `publish_rate`.
+    let limiter = (publish_rate > 0.0).then(|| Arc::new(TokenBucket::new(publish_rate, publish_rate)));
+
     let start = Instant::now();
-    let mut handles = vec![];
+    let mut tasks = JoinSet::new();
     for thread_id in 0..threads {
-        let handle = tokio::spawn(async move {
+        let token = token.clone();
+        let limiter = limiter.clone();
+        tasks.spawn(async move {
             tracing::info!("Started thread t{thread_id}");
-            publish_records(num_records / threads, thread_id, verify).await
+            publish_records(
+                num_records / threads,
+                thread_id,
+                verify,
+                &token,
+                limiter.as_deref(),
+            )
+            .await
         });
-        handles.push(handle);
-    }
-
-    loop {
-        let all_finished = handles
-            .iter()
-            .map(|handle| handle.is_finished())
-            .reduce(|a, b| a && b)
-            .unwrap();
-        if all_finished {
-            break;
-        }
-        if ctrlc_pressed.load(Ordering::Relaxed) {
-            break;
-        }
-        sleep(Duration::from_millis(250)).await;
-    }
-
-    if ctrlc_pressed.load(Ordering::Relaxed) {
-        process::exit(0);
     }
 
     let mut all_result = vec![];
-    for handle in handles {
-        let keys = handle.await.unwrap();
+    let mut all_latencies = LatencyHistogram::new();
+    while let Some(joined) = tasks.join_next().await {
+        let (keys, latencies) = joined.unwrap();
         all_result.extend(keys);
+        all_latencies.merge(&latencies);
     }
 
-    let rate = all_result.len() as f64 / start.elapsed().as_secs() as f64;
-    tracing::info!(
-        "Published {} keys in {} seconds at {rate:.2} keys/s",
-        all_result.len(),
-        start.elapsed().as_secs()
-    );
+    let elapsed_secs = start.elapsed().as_secs().max(1);
+    let rate = all_result.len() as f64 / elapsed_secs as f64;
+    if publish_rate > 0.0 {
+        tracing::info!(
+            "Published {} keys in {} seconds at {rate:.2} keys/s (requested {publish_rate:.2} keys/s)",
+            all_result.len(),
+            elapsed_secs,
+        );
+    } else {
+        tracing::info!(
+            "Published {} keys in {} seconds at {rate:.2} keys/s",
+            all_result.len(),
+            elapsed_secs,
+        );
+    }
+
+    (all_result, all_latencies)
+}
 
-    all_result
+/// Writes the publish latency percentiles to `latency_percentiles.csv`.
+fn write_latency_csv(latencies: &LatencyHistogram) -> anyhow::Result<()> {
+    let file = File::create("latency_percentiles.csv")?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "pass,p50_us,p90_us,p99_us,max_us")?;
+    writeln!(
+        writer,
+        "publish,{},{},{},{}",
+        latencies.p50(),
+        latencies.p90(),
+        latencies.p99(),
+        latencies.max()
+    )?;
+    writer.flush()?;
+    Ok(())
 }
@@ -0,0 +1,52 @@
+//! A minimal, dependency-free HTTP endpoint for scraping live metrics.
+//!
+//! This deliberately isn't a real web server: it answers every request (regardless
+//! of path or method) with the current snapshot as a JSON body, which is all an
+//! external collector polling in a loop needs.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Binds `addr` and serves `snapshot()`'s current return value as a JSON body to
+/// every connection, until the process exits or the task is aborted. Intended to
+/// be `tokio::spawn`ed alongside a publish/churn loop.
+pub async fn serve_json<F>(addr: SocketAddr, snapshot: F) -> anyhow::Result<()>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let snapshot = Arc::new(snapshot);
+    tracing::info!("Serving metrics on http://{addr}");
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // A single transient accept error (e.g. a transient EMFILE under load)
+                // shouldn't kill the endpoint for the rest of a multi-hour run.
+                tracing::warn!("Failed to accept metrics connection: {e:?}");
+                continue;
+            }
+        };
+        let snapshot = Arc::clone(&snapshot);
+        tokio::spawn(async move {
+            // Drain (and discard) the request; path/method don't matter, there's
+            // only one thing this endpoint serves.
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+
+            let body = snapshot();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
@@ -1,13 +1,72 @@
 use clap::Parser;
 use mainline::Dht;
 use pkarr::{Client, Keypair, PublicKey, SignedPacket};
+use pkarr_churn::helpers::count_dht_nodes_storing_packet;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{BufWriter, Write},
     time::{Duration, Instant},
 };
-use tokio::time::sleep;
+use tokio::{sync::mpsc, time::sleep};
+
+/// A single line to be appended to one of the experiment's CSV outputs.
+///
+/// The churn loop only ever sends these; a dedicated writer task owns the files and
+/// does the actual (and potentially slow) `writeln!`/`flush`, so a slow disk can't
+/// stall the polling cadence.
+enum LogEvent {
+    NodeDecay { timestamp_s: u64, pubkey: PublicKey, nodes_count: u8 },
+    Churn { pubkey: PublicKey, churn_time_s: u64 },
+    NodesStoring { node_count: u32, timestamp_s: u64 },
+}
+
+/// Spawns the writer task that owns all three CSV files (`nodes_decay.csv`,
+/// `churns.csv`, `nodes_storing.csv`) and applies [`LogEvent`]s sent to it, flushing
+/// on an interval rather than after every line. The task exits once `events` closes
+/// (i.e. every sender has been dropped).
+fn spawn_csv_writer(mut events: mpsc::Receiver<LogEvent>) -> tokio::task::JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let mut nodes_writer = BufWriter::new(File::create("nodes_decay.csv")?);
+        writeln!(nodes_writer, "timestamp_s,pubkey,nodes_count")?;
+
+        let mut churn_writer = BufWriter::new(File::create("churns.csv")?);
+        writeln!(churn_writer, "pubkey,churn_time_s")?;
+
+        let mut nodes_storing_writer = BufWriter::new(File::create("nodes_storing.csv")?);
+        writeln!(nodes_storing_writer, "node_count,timestamp")?;
+
+        let mut flush_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    match event {
+                        Some(LogEvent::NodeDecay { timestamp_s, pubkey, nodes_count }) => {
+                            writeln!(nodes_writer, "{timestamp_s},{pubkey},{nodes_count}")?;
+                        }
+                        Some(LogEvent::Churn { pubkey, churn_time_s }) => {
+                            writeln!(churn_writer, "{pubkey},{churn_time_s}")?;
+                        }
+                        Some(LogEvent::NodesStoring { node_count, timestamp_s }) => {
+                            writeln!(nodes_storing_writer, "{node_count},{timestamp_s}")?;
+                        }
+                        None => break,
+                    }
+                }
+                _ = flush_interval.tick() => {
+                    nodes_writer.flush()?;
+                    churn_writer.flush()?;
+                    nodes_storing_writer.flush()?;
+                }
+            }
+        }
+
+        nodes_writer.flush()?;
+        churn_writer.flush()?;
+        nodes_storing_writer.flush()?;
+        Ok(())
+    })
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -31,6 +90,11 @@ struct Cli {
     /// Maximum duration (in hours) for the churn monitoring phase
     #[arg(long, default_value_t = 200)]
     max_hours: u64,
+
+    /// A key must remain continuously unresolved for this many seconds before it is
+    /// confirmed as churned and logged to churns.csv, filtering out transient query failures
+    #[arg(long, default_value_t = 60)]
+    churn_grace_s: u64,
 }
 
 #[tokio::main]
@@ -53,40 +117,32 @@ async fn main() -> anyhow::Result<()> {
     println!("Waiting one minute before starting churn monitoring.");
     sleep(Duration::from_secs(60)).await;
 
-    // Open CSV files.
-
-    // 1. nodes_decay.csv: Logs changes for individual keys.
-    let nodes_file = File::create("nodes_decay.csv")?;
-    let mut nodes_writer = BufWriter::new(nodes_file);
-    writeln!(nodes_writer, "timestamp_s,pubkey,nodes_count")?;
-
-    // 2. churns.csv: Logs when a key goes unresolved (nodes count becomes 0).
-    let churn_file = File::create("churns.csv")?;
-    let mut churn_writer = BufWriter::new(churn_file);
-    writeln!(churn_writer, "pubkey,churn_time_s")?;
-
-    // 3. nodes_storing.csv: Logs the total global number of nodes across all keys whenever it decreases.
-    let nodes_storing_file = File::create("nodes_storing.csv")?;
-    let mut nodes_storing_writer = BufWriter::new(nodes_storing_file);
-    writeln!(nodes_storing_writer, "node_count,timestamp")?;
+    // A dedicated writer task owns all three CSV files; the churn loop below only ever
+    // sends it `LogEvent`s and never touches the filesystem itself.
+    let (log_tx, log_rx) = mpsc::channel(1024);
+    let writer_task = spawn_csv_writer(log_rx);
 
     // Track the last known node count per key.
     let mut last_nodes_count: HashMap<PublicKey, u8> = HashMap::new();
 
     let max_duration = Duration::from_secs(cli.max_hours * 3600);
+    let churn_grace = Duration::from_secs(cli.churn_grace_s);
     run_churn_loop(
         dht,
         published_records,
         cli.stop_fraction,
         cli.sleep_duration_ms,
         max_duration,
-        &mut nodes_writer,
+        churn_grace,
         &mut last_nodes_count,
-        &mut churn_writer,
-        &mut nodes_storing_writer,
+        log_tx,
     )
     .await?;
 
+    // `run_churn_loop` owned (and has now dropped) the sender, so the writer task's
+    // channel is closed and it will flush and exit on its own.
+    writer_task.await??;
+
     Ok(())
 }
 
@@ -128,9 +184,11 @@ async fn publish_records(
 }
 
 /// The churn loop monitors every published record and, for each one:
-/// - Queries how many nodes (using `count_dht_nodes_storing_packet`) currently store its packet.
+/// - Queries how many nodes (using `pkarr_churn::helpers::count_dht_nodes_storing_packet`)
+///   currently store its packet.
 /// - Logs any change in the per-key node count to "nodes_decay.csv".
-/// - Marks a key as churned (and logs it to "churns.csv") when its node count falls to 0.
+/// - Marks a key as churned (and logs it to "churns.csv") once its node count has stayed at 0 for
+///   the full `churn_grace` period, so a single transient query failure isn't mistaken for churn.
 /// - Additionally, calculates the global total number of nodes across all keys and,
 ///   whenever that total decreases, logs the new total and timestamp to "nodes_storing.csv".
 async fn run_churn_loop(
@@ -139,13 +197,15 @@ async fn run_churn_loop(
     stop_fraction: f64,
     sleep_duration_ms: u64,
     max_duration: Duration,
-    nodes_writer: &mut BufWriter<File>,
+    churn_grace: Duration,
     last_nodes_count: &mut HashMap<PublicKey, u8>,
-    churn_writer: &mut BufWriter<File>,
-    nodes_storing_writer: &mut BufWriter<File>,
+    log_tx: mpsc::Sender<LogEvent>,
 ) -> anyhow::Result<()> {
     let total_keys = verified_records.len();
+    // Keys currently unresolved, mapped to the instant they first went to zero.
     let mut potential_churn: HashMap<PublicKey, Instant> = HashMap::new();
+    // Keys already confirmed (and logged) as churned, so we don't log them twice.
+    let mut confirmed_churn: HashSet<PublicKey> = HashSet::new();
     let churn_start = Instant::now();
     let mut last_global_count: Option<u32> = None;
 
@@ -159,9 +219,11 @@ async fn run_churn_loop(
         for (pubkey, publish_instant) in &verified_records {
             sleep(Duration::from_millis(sleep_duration_ms)).await;
 
-            // Query the current number of nodes storing the packet.
+            // Query how many nodes are currently storing the packet.
             let nodes_count = count_dht_nodes_storing_packet(pubkey, &dht).await;
 
+            let timestamp_s = churn_start.elapsed().as_secs();
+
             // If the per-key node count changed, log the update.
             let record_changed = match last_nodes_count.get(pubkey) {
                 Some(&last) => last != nodes_count,
@@ -169,49 +231,62 @@ async fn run_churn_loop(
             };
 
             if record_changed {
-                let timestamp = churn_start.elapsed().as_secs();
-                writeln!(nodes_writer, "{timestamp},{pubkey},{nodes_count}")?;
-                nodes_writer.flush()?;
+                log_tx
+                    .send(LogEvent::NodeDecay { timestamp_s, pubkey: pubkey.clone(), nodes_count })
+                    .await
+                    .map_err(|_| anyhow::anyhow!("CSV writer task exited unexpectedly"))?;
                 last_nodes_count.insert(pubkey.clone(), nodes_count);
             }
 
-            // Check churn status: if no nodes hold the packet, mark it as churned.
+            // Check churn status: if no nodes hold the packet, track it as potentially churned.
             if nodes_count > 0 {
                 if potential_churn.remove(pubkey).is_some() {
+                    confirmed_churn.remove(pubkey);
                     println!("Key {} recovered; clearing churn record.", pubkey);
                 } else {
                     println!("Key {} is resolvable on {} nodes.", pubkey, nodes_count);
                 }
             } else {
-                if !potential_churn.contains_key(pubkey) {
-                    potential_churn.insert(pubkey.clone(), Instant::now());
-                    println!("Key {} unresolved; marking failure timestamp.", pubkey);
-                    let churn_time = Instant::now().duration_since(*publish_instant).as_secs();
-                    writeln!(churn_writer, "{pubkey},{churn_time}")?;
-                    churn_writer.flush()?;
-                } else {
+                let first_zero = *potential_churn.entry(pubkey.clone()).or_insert_with(Instant::now);
+                if confirmed_churn.contains(pubkey) {
                     println!("Key {} remains unresolved.", pubkey);
+                } else if first_zero.elapsed() >= churn_grace {
+                    confirmed_churn.insert(pubkey.clone());
+                    println!("Key {} unresolved for the full grace period; confirming churn.", pubkey);
+                    // Use when the key was first seen unresolved, not the confirmation
+                    // instant (which is `churn_grace` later), so the recorded churn time
+                    // isn't systematically inflated by the grace period.
+                    let churn_time_s = first_zero.duration_since(*publish_instant).as_secs();
+                    log_tx
+                        .send(LogEvent::Churn { pubkey: pubkey.clone(), churn_time_s })
+                        .await
+                        .map_err(|_| anyhow::anyhow!("CSV writer task exited unexpectedly"))?;
+                } else {
+                    println!("Key {} unresolved; within grace period.", pubkey);
                 }
             }
         }
 
+        let timestamp_s = churn_start.elapsed().as_secs();
+
         // Compute the global node count across all keys.
         let current_global_count: u32 = last_nodes_count.values().map(|&v| v as u32).sum();
         // If the global count decreased, log the new total.
         if let Some(prev) = last_global_count {
             if current_global_count < prev {
-                let timestamp = churn_start.elapsed().as_secs();
-                writeln!(nodes_storing_writer, "{current_global_count},{timestamp}")?;
-                nodes_storing_writer.flush()?;
+                log_tx
+                    .send(LogEvent::NodesStoring { node_count: current_global_count, timestamp_s })
+                    .await
+                    .map_err(|_| anyhow::anyhow!("CSV writer task exited unexpectedly"))?;
                 println!(
                     "Global node count decreased from {} to {} at {} seconds.",
-                    prev, current_global_count, timestamp
+                    prev, current_global_count, timestamp_s
                 );
             }
         }
         last_global_count = Some(current_global_count);
 
-        let churn_fraction = potential_churn.len() as f64 / total_keys as f64;
+        let churn_fraction = confirmed_churn.len() as f64 / total_keys as f64;
         println!("Current churn fraction: {:.2}%", churn_fraction * 100.0);
 
         // Stop if the fraction of churned keys reaches the specified threshold.
@@ -225,19 +300,3 @@ async fn run_churn_loop(
     }
     Ok(())
 }
-
-/// Asynchronous helper to count the number of DHT nodes storing a given packet.
-/// This spawns a blocking task to iterate over the responses returned by `dht.get_mutable()`.
-pub async fn count_dht_nodes_storing_packet(pubkey: &PublicKey, client: &Dht) -> u8 {
-    let dht_clone = client.clone();
-    let pubkey_clone = pubkey.clone();
-    let handle = tokio::task::spawn_blocking(move || {
-        let stream = dht_clone.get_mutable(pubkey_clone.as_bytes(), None, None);
-        let mut response_count: u8 = 0;
-        for _ in stream {
-            response_count += 1;
-        }
-        response_count
-    });
-    handle.await.unwrap()
-}
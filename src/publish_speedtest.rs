@@ -10,7 +10,8 @@
 //! - **Metrics Tracking**: Keeps track of success and failure counts for publishing.
 //! - **Reporting**: Every _N_ successful publishes (configurable) it prints:
 //!   - Total attempts and success/failure ratio.
-//!   - Average time per successful publish.
+//!   - Average time per successful publish (elapsed wall time divided by successes), plus
+//!     p50/p90/p99/p999 and max publish latency.
 //!   - Estimated number of keys published in an hour.
 //!
 //! ## Configuration Options
@@ -18,11 +19,23 @@
 //! - `ttl_s`: TTL (in seconds) for each published record (default: 604800 seconds or 1 week).
 //! - `report_interval`: Print statistics every N successful publishes (default: 1000).
 //! - `threads`: Number of Tokio tasks (threads) to spawn (default: 64).
+//! - `target_rate`: Caps the aggregate publish rate across all threads via a shared
+//!   token-bucket limiter (default: 0, i.e. unlimited/saturation throughput). Use this
+//!   to measure success ratio and latency at a fixed offered load instead of only at
+//!   the DHT's saturation point.
+//! - `metrics_format`: `text` (default) prints the existing human-readable reports;
+//!   `json` emits one NDJSON object per report to stdout in place of the text report.
+//! - `metrics_addr`: If set, serves the current counters/latency percentiles as JSON
+//!   over HTTP at this address (see [`pkarr_churn::metrics_server`]), so throughput can
+//!   be monitored live without parsing stdout.
 //!
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use pkarr::{Client, Keypair, SignedPacket};
+use pkarr_churn::latency::AtomicLatencyHistogram;
+use pkarr_churn::{serve_json, TokenBucket};
 use std::{
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -44,6 +57,50 @@ struct Cli {
     /// Number of Tokio threads to use
     #[arg(long, default_value_t = 128)]
     threads: usize,
+
+    /// Target aggregate publish rate in publishes/sec across all threads (0 = unlimited, i.e. saturation throughput)
+    #[arg(long, default_value_t = 0.0)]
+    target_rate: f64,
+
+    /// How to emit periodic progress reports
+    #[arg(long, value_enum, default_value_t = MetricsFormat::Text)]
+    metrics_format: MetricsFormat,
+
+    /// If set, serves the current counters/latencies as JSON over HTTP at this address
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MetricsFormat {
+    Text,
+    Json,
+}
+
+/// Renders the current counters and latency percentiles as a single NDJSON object.
+fn metrics_json(
+    success_count: &AtomicUsize,
+    failure_count: &AtomicUsize,
+    latencies: &AtomicLatencyHistogram,
+    start_time: Instant,
+) -> String {
+    let successes = success_count.load(Ordering::Relaxed);
+    let failures = failure_count.load(Ordering::Relaxed);
+    let attempts = successes + failures;
+    let success_ratio = if attempts == 0 { 0.0 } else { successes as f64 / attempts as f64 };
+    format!(
+        "{{\"elapsed_s\":{:.3},\"attempts\":{},\"successes\":{},\"failures\":{},\"success_ratio\":{:.4},\"latency_us\":{{\"p50\":{},\"p90\":{},\"p99\":{},\"p999\":{},\"max\":{}}}}}",
+        start_time.elapsed().as_secs_f64(),
+        attempts,
+        successes,
+        failures,
+        success_ratio,
+        latencies.p50(),
+        latencies.p90(),
+        latencies.p99(),
+        latencies.p999(),
+        latencies.max(),
+    )
 }
 
 #[tokio::main]
@@ -53,22 +110,52 @@ async fn main() -> anyhow::Result<()> {
     // Global atomic counters for successes and failures.
     let success_count = Arc::new(AtomicUsize::new(0));
     let failure_count = Arc::new(AtomicUsize::new(0));
+    let latencies = Arc::new(AtomicLatencyHistogram::new());
+    // One limiter shared across every task, so `target_rate` bounds the
+    // aggregate offered load rather than each task's own rate.
+    let limiter = (cli.target_rate > 0.0)
+        .then(|| Arc::new(TokenBucket::new(cli.target_rate, cli.target_rate)));
+    if limiter.is_some() {
+        println!("Target publish rate: {} publishes/sec", cli.target_rate);
+    }
     let start_time = Instant::now();
 
+    if let Some(addr) = cli.metrics_addr {
+        let success_count = Arc::clone(&success_count);
+        let failure_count = Arc::clone(&failure_count);
+        let latencies = Arc::clone(&latencies);
+        tokio::spawn(async move {
+            let result = serve_json(addr, move || {
+                metrics_json(&success_count, &failure_count, &latencies, start_time)
+            })
+            .await;
+            if let Err(e) = result {
+                eprintln!("Metrics server on {addr} exited: {e:?}");
+            }
+        });
+    }
+
     // Spawn the specified number of concurrent tasks.
     let mut handles = Vec::with_capacity(cli.threads);
     for _ in 0..cli.threads {
         let success_count = Arc::clone(&success_count);
         let failure_count = Arc::clone(&failure_count);
+        let latencies = Arc::clone(&latencies);
+        let limiter = limiter.clone();
         let ttl_s = cli.ttl_s;
         let report_interval = cli.report_interval;
         let start_time = start_time.clone();
+        let metrics_format = cli.metrics_format;
 
         let handle = tokio::spawn(async move {
             // Create a new client for this thread.
             let client = Client::builder().build().expect("failed to create client");
 
             loop {
+                if let Some(limiter) = &limiter {
+                    limiter.acquire().await;
+                }
+
                 // Create a new record.
                 let keypair = Keypair::random();
                 let packet = match SignedPacket::builder()
@@ -87,29 +174,46 @@ async fn main() -> anyhow::Result<()> {
                     }
                 };
 
-                // Publish the record.
+                // Publish the record, timing how long it takes.
+                let publish_start = Instant::now();
                 match client.publish(&packet, None).await {
                     Ok(_) => {
+                        latencies.record(publish_start.elapsed().as_micros() as u64);
                         // Increment the success counter.
                         let successes = success_count.fetch_add(1, Ordering::Relaxed) + 1;
                         // Report statistics every N successful publishes.
                         if successes % report_interval == 0 {
-                            let failures = failure_count.load(Ordering::Relaxed);
-                            let total_attempts = successes + failures;
-                            let elapsed = start_time.elapsed().as_secs_f64();
-                            let avg_publish_time = elapsed / (successes as f64);
-                            let estimated_per_hour = (successes as f64 / elapsed) * 3600.0;
-                            println!(
-                                "Total attempts: {} | Success: {} | Failures: {} | Success Ratio: {:.2}%",
-                                total_attempts,
-                                successes,
-                                failures,
-                                (successes as f64 / total_attempts as f64) * 100.0,
-                            );
-                            println!(
-                                "Avg time per successful publish: {:.6} s | Estimated publishes per hour: {:.0}",
-                                avg_publish_time, estimated_per_hour
-                            );
+                            if matches!(metrics_format, MetricsFormat::Json) {
+                                println!(
+                                    "{}",
+                                    metrics_json(&success_count, &failure_count, &latencies, start_time)
+                                );
+                            } else {
+                                let failures = failure_count.load(Ordering::Relaxed);
+                                let total_attempts = successes + failures;
+                                let elapsed = start_time.elapsed().as_secs_f64();
+                                let avg_publish_time = elapsed / (successes as f64);
+                                let estimated_per_hour = (successes as f64 / elapsed) * 3600.0;
+                                println!(
+                                    "Total attempts: {} | Success: {} | Failures: {} | Success Ratio: {:.2}%",
+                                    total_attempts,
+                                    successes,
+                                    failures,
+                                    (successes as f64 / total_attempts as f64) * 100.0,
+                                );
+                                println!(
+                                    "Avg time per successful publish: {:.6} s | Estimated publishes per hour: {:.0}",
+                                    avg_publish_time, estimated_per_hour
+                                );
+                                println!(
+                                    "Publish latency (us): p50={} p90={} p99={} p999={} max={}",
+                                    latencies.p50(),
+                                    latencies.p90(),
+                                    latencies.p99(),
+                                    latencies.p999(),
+                                    latencies.max(),
+                                );
+                            }
                         }
                     }
                     Err(e) => {
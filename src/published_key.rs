@@ -1,10 +1,17 @@
 use pkarr::{Keypair, PublicKey, SignedPacket, Timestamp};
+use tokio::time::Instant;
 
 
 #[derive(Debug, Clone)]
 pub struct PublishedKey {
     pub key: Keypair,
     pub created_at: Timestamp,
+    /// When this key was constructed, as a monotonic [`Instant`]. For keys built right
+    /// before their first publish (the common case), this doubles as the original
+    /// publish instant, so callers tracking republish intervals (e.g.
+    /// [`crate::churn_experiment::ChurnExperiment::run_churn_loop`]) can seed from it
+    /// instead of from an unrelated loop-start instant.
+    pub published_at: Instant,
     pub churned_at: Option<Timestamp>
 }
 
@@ -13,6 +20,7 @@ impl PublishedKey {
         Self {
             key,
             created_at: Timestamp::now(),
+            published_at: Instant::now(),
             churned_at: None
         }
     }
@@ -22,6 +30,7 @@ impl PublishedKey {
         Self {
             key: Keypair::random(),
             created_at: Timestamp::now(),
+            published_at: Instant::now(),
             churned_at: None
         }
     }
@@ -44,11 +53,16 @@ impl PublishedKey {
         self.churned_at = None;
     }
     pub fn create_packet(&self) -> SignedPacket {
+        self.create_packet_with_ttl(300)
+    }
+
+    /// Same as [`Self::create_packet`] but with a caller-provided TTL (in seconds).
+    pub fn create_packet_with_ttl(&self, ttl_s: u32) -> SignedPacket {
         SignedPacket::builder()
             .txt(
                 "_experiment".try_into().unwrap(),
                 "dht-test".try_into().unwrap(),
-                300,
+                ttl_s,
             )
             .sign(&self.key).unwrap()
     }
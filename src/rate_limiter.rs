@@ -0,0 +1,93 @@
+//! A simple token-bucket rate limiter for holding an operation at a fixed
+//! offered load, shared across concurrent tasks.
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+struct TokenBucketState {
+    available: f64,
+    last_refill: Instant,
+}
+
+/// Limits callers to `rate` operations/sec, allowing bursts of up to
+/// `burst` tokens. Refills based on elapsed wall-clock time, so it can be
+/// shared (behind an `Arc`) across many tasks to cap their *aggregate*
+/// rate rather than each task's own rate.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<TokenBucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(TokenBucketState {
+                available: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a token is available, then consumes it.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.available = (state.available + elapsed * self.rate).min(self.burst);
+                state.last_refill = now;
+
+                if state.available >= 1.0 {
+                    state.available -= 1.0;
+                    None
+                } else {
+                    Some((1.0 - state.available) / self.rate)
+                }
+            };
+
+            match wait {
+                None => return,
+                Some(secs) => sleep(Duration::from_secs_f64(secs.max(0.0))).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_up_to_the_configured_size_without_waiting() {
+        let bucket = TokenBucket::new(1.0, 3.0);
+        // All three initial tokens should be available immediately.
+        for _ in 0..3 {
+            timeout(Duration::from_millis(1), bucket.acquire()).await.expect("should not block");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blocks_once_the_burst_is_exhausted_until_refilled() {
+        let bucket = TokenBucket::new(1.0, 1.0);
+        bucket.acquire().await;
+        // The single token was just consumed, so the next acquire must wait
+        // for a refill rather than returning immediately.
+        timeout(Duration::from_millis(1), bucket.acquire())
+            .await
+            .expect_err("should block until refilled");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_configured_rate() {
+        let bucket = TokenBucket::new(10.0, 1.0);
+        bucket.acquire().await;
+        // At 10 tokens/sec, a full token should be back after ~100ms.
+        tokio::time::advance(Duration::from_millis(150)).await;
+        timeout(Duration::from_millis(1), bucket.acquire()).await.expect("should have refilled");
+    }
+}
@@ -7,6 +7,7 @@ use std::{
 };
 
 use pkarr::{Client, PublicKey};
+use pkarr_churn::ChurnExperiment;
 use tracing::Level;
 use tracing_subscriber;
 
@@ -18,6 +19,7 @@ async fn main() {
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
     let client = Client::builder().no_relays().cache_size(0).build().unwrap();
+    let experiment = ChurnExperiment::builder().build();
 
     let (keys, publish_time) = load_keys_from_disk().unwrap();
 
@@ -27,23 +29,17 @@ async fn main() {
     loop {
         attempts += 1;
 
-        // Sample a key
-        let mut bytes = [0; 8];
-        getrandom::fill(&mut bytes).expect("getrandom");
+        let outcome = experiment.resolve_sample(&client, &keys).await;
 
-        let index = u64::from_le_bytes(bytes) as usize % keys.len();
-        let key = keys[index].clone();
-
-        if client.resolve(&key).await.is_some() {
+        if outcome.resolved {
             success += 1;
-
-            println!("{}/{} Successfully resolved {}", success, attempts, key,);
+            println!("{}/{} Successfully resolved {}", success, attempts, outcome.key);
         } else {
             println!(
                 "{}/{} Failed to resolve a key {} after {} seconds of publishing.",
                 attempts - success,
                 attempts,
-                key,
+                outcome.key,
                 publish_time.elapsed().unwrap().as_secs(),
             );
         };